@@ -1,10 +1,11 @@
+use std::collections::VecDeque;
 use std::fmt;
 use std::future::Future;
 use std::pin::Pin;
 use std::task::{Context, Poll};
 
 use crate::runtime::Handle;
-use crate::task::{JoinError, JoinHandle, LocalSet};
+use crate::task::{AbortHandle, JoinError, JoinHandle, LocalSet};
 use crate::util::IdleNotifiedSet;
 
 /// A collection of tasks spawned on a Tokio runtime.
@@ -38,15 +39,70 @@ use crate::util::IdleNotifiedSet;
 ///     }
 /// }
 /// ```
-pub struct TaskSet<T> {
-    inner: IdleNotifiedSet<JoinHandle<T>>,
+///
+/// Tasks can optionally be spawned with a caller-supplied key via [`spawn_with_key`], which is
+/// then handed back alongside the task's output by [`join_one_with_key`]. This is useful when
+/// fanning work out over a set and needing to know which input produced a given result.
+///
+/// [`spawn_with_key`]: TaskSet::spawn_with_key
+/// [`join_one_with_key`]: TaskSet::join_one_with_key
+///
+/// `TaskSet<T>` also implements [`Stream`], yielding each task's output as it completes and
+/// ending once the set is empty. This lets a `TaskSet` be driven with `StreamExt` combinators
+/// such as `buffered` or `for_each_concurrent` instead of a manual `join_one` loop.
+///
+/// [`Stream`]: futures_core::Stream
+///
+/// A `TaskSet` created with [`with_capacity_limit`] caps how many tasks run concurrently;
+/// [`spawn_when_ready`] waits for a free slot instead of spawning unboundedly, which keeps
+/// memory bounded when fanning out over very large workloads.
+///
+/// [`with_capacity_limit`]: TaskSet::with_capacity_limit
+/// [`spawn_when_ready`]: TaskSet::spawn_when_ready
+pub struct TaskSet<T, K = ()> {
+    inner: IdleNotifiedSet<(K, JoinHandle<T>)>,
+    limit: Option<usize>,
+    // Results popped out of `inner` by `spawn_when_ready` while it was waiting for a free slot.
+    // These are handed out by `join_one`/`join_one_with_key` (oldest first) before `inner` is
+    // polled again, so no completion is lost while waiting for a slot. This is intentionally
+    // separate from the wait performed by `spawn_when_ready` itself, which always polls `inner`
+    // directly: if it instead consumed from this queue, a result freed by one call could be
+    // replayed to every later call without `inner` ever shrinking, defeating the capacity limit.
+    buffered: VecDeque<Result<(K, T), (K, JoinError)>>,
 }
 
-impl<T> TaskSet<T> {
+impl<T, K> TaskSet<T, K> {
     /// Create a new `TaskSet`.
     pub fn new() -> Self {
         Self {
             inner: IdleNotifiedSet::new(),
+            limit: None,
+            buffered: VecDeque::new(),
+        }
+    }
+
+    /// Create a new `TaskSet` that allows at most `limit` tasks to run concurrently.
+    ///
+    /// Once the set holds `limit` tasks, [`spawn_when_ready`] (or, for keyed sets,
+    /// [`spawn_with_key_when_ready`]) waits for one of them to finish before spawning the next,
+    /// which keeps memory bounded when fanning out over a very large number of futures. Plain
+    /// [`spawn`]/[`spawn_with_key`] ignore the limit entirely, so use the `_when_ready` variants
+    /// to actually enforce it.
+    ///
+    /// A `limit` of `0` is degenerate: every call to `spawn_when_ready` waits for a task to
+    /// complete before spawning, but since the set starts (and, once drained, stays) empty,
+    /// there is nothing to wait for on the first call, so it spawns immediately just like
+    /// `limit == 1`. Passing `0` is allowed but does not actually prevent any task from running.
+    ///
+    /// [`spawn`]: TaskSet::spawn
+    /// [`spawn_with_key`]: TaskSet::spawn_with_key
+    /// [`spawn_when_ready`]: TaskSet::spawn_when_ready
+    /// [`spawn_with_key_when_ready`]: TaskSet::spawn_with_key_when_ready
+    pub fn with_capacity_limit(limit: usize) -> Self {
+        Self {
+            inner: IdleNotifiedSet::new(),
+            limit: Some(limit),
+            buffered: VecDeque::new(),
         }
     }
 
@@ -61,62 +117,239 @@ impl<T> TaskSet<T> {
     }
 }
 
+impl<T: 'static, K: 'static> TaskSet<T, K> {
+    /// Spawn the provided task on the task set, associating it with the given `key`.
+    ///
+    /// The key is returned alongside the task's output by [`join_one_with_key`], even if the
+    /// task panics, which avoids the common workaround of spawning `async move { (key,
+    /// task.await) }` that loses the key on panic.
+    ///
+    /// # Panics
+    ///
+    /// This method panics if called outside of a Tokio runtime.
+    ///
+    /// [`join_one_with_key`]: TaskSet::join_one_with_key
+    pub fn spawn_with_key<F>(&mut self, key: K, task: F) -> AbortHandle
+    where
+        F: Future<Output = T>,
+        F: Send + 'static,
+        T: Send,
+    {
+        self.insert(key, crate::spawn(task))
+    }
+
+    /// Spawn the provided task once the set has a free slot, associating it with the given
+    /// `key`, and returning an [`AbortHandle`] for it.
+    ///
+    /// If the `TaskSet` was created with [`with_capacity_limit`] and already holds that many
+    /// tasks, this waits for one of them to complete before spawning `task`, exactly like
+    /// [`spawn_when_ready`] but for keyed sets. The completed task's key and result are buffered
+    /// and handed back by the next call to [`join_one_with_key`], so no result is lost while
+    /// waiting for a slot.
+    ///
+    /// If the set has no limit, this behaves exactly like [`spawn_with_key`].
+    ///
+    /// [`with_capacity_limit`]: TaskSet::with_capacity_limit
+    /// [`spawn_when_ready`]: TaskSet::spawn_when_ready
+    /// [`join_one_with_key`]: TaskSet::join_one_with_key
+    /// [`spawn_with_key`]: TaskSet::spawn_with_key
+    pub async fn spawn_with_key_when_ready<F>(&mut self, key: K, task: F) -> AbortHandle
+    where
+        F: Future<Output = T>,
+        F: Send + 'static,
+        T: Send,
+    {
+        if let Some(limit) = self.limit {
+            if self.len() >= limit {
+                if let Some(res) = crate::future::poll_fn(|cx| self.poll_inner_one(cx))
+                    .await
+                    .transpose()
+                {
+                    self.buffered.push_back(res);
+                }
+            }
+        }
+
+        self.spawn_with_key(key, task)
+    }
+
+    fn insert(&mut self, key: K, jh: JoinHandle<T>) -> AbortHandle {
+        let abort = jh.abort_handle();
+        let mut entry = self.inner.insert_idle((key, jh));
+
+        // Set the waker that is notified when the task completes.
+        entry.with_value_and_context(|(_, jh), ctx| jh.set_join_waker(ctx.waker()));
+
+        abort
+    }
+
+    /// Aborts all tasks without removing them from the set.
+    fn abort_all(&mut self) {
+        self.inner.for_each(|(_, jh)| jh.abort());
+    }
+
+    /// Wait until one of the tasks in the set completes and returns its output together with the
+    /// key it was spawned with.
+    ///
+    /// Returns `None` if the set is empty.
+    ///
+    /// # Cancel Safety
+    ///
+    /// This method is cancel safe. If `join_one_with_key` is used as the event in a
+    /// `tokio::select!` statement and some other branch completes first, it is guaranteed that
+    /// no tasks were removed from this `TaskSet`.
+    pub async fn join_one_with_key(&mut self) -> Result<Option<(K, T)>, (K, JoinError)> {
+        crate::future::poll_fn(|cx| self.poll_join_one_with_key(cx)).await
+    }
+
+    /// Poll for one of the tasks in the set to complete, yielding its key alongside the result.
+    ///
+    /// This behaves exactly like [`poll_join_one`](TaskSet::poll_join_one), except that the key
+    /// the completed task was spawned with is carried out of the removed entry next to the
+    /// output (or inside the error, if the task panicked or was aborted).
+    pub fn poll_join_one_with_key(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<(K, T)>, (K, JoinError)>> {
+        // `spawn_when_ready` may have already popped completed tasks while waiting for a free
+        // slot; hand those out, oldest first, before looking at the set itself.
+        if let Some(res) = self.buffered.pop_front() {
+            return Poll::Ready(res.map(Some));
+        }
+
+        self.poll_inner_one(cx)
+    }
+
+    /// Poll `inner` directly for one completed task, ignoring `buffered`.
+    ///
+    /// This is used by `spawn_when_ready` to wait for a genuinely free slot: going through
+    /// `poll_join_one_with_key` instead would let it repeatedly observe a result that a previous
+    /// call already buffered, without ever removing a new entry from `inner`.
+    fn poll_inner_one(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<(K, T)>, (K, JoinError)>> {
+        // The call to `pop_notified` moves the entry to the `idle` list. It is moved back to
+        // the `notified` list if the waker is notified in the `poll` call below.
+        let mut entry = match self.inner.pop_notified(cx.waker()) {
+            Some(entry) => entry,
+            None => {
+                if self.is_empty() {
+                    return Poll::Ready(Ok(None));
+                } else {
+                    // The waker was set by `pop_notified`.
+                    return Poll::Pending;
+                }
+            }
+        };
+
+        let res = entry.with_value_and_context(|(_, jh), ctx| Pin::new(jh).poll(ctx));
+
+        if let Poll::Ready(res) = res {
+            let (key, _jh) = entry.remove();
+            Poll::Ready(match res {
+                Ok(value) => Ok(Some((key, value))),
+                Err(err) => Err((key, err)),
+            })
+        } else {
+            // A JoinHandle generally wont emit a wakeup without being ready unless
+            // the coop limit has been reached. We yield to the executor in this
+            // case.
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        }
+    }
+}
+
 impl<T: 'static> TaskSet<T> {
-    /// Spawn the provided task on the task set.
+    /// Spawn the provided task on the task set, returning an [`AbortHandle`] that can be used to
+    /// remotely cancel just this task.
+    ///
+    /// The returned handle stays valid even after the task finishes; aborting through it then has
+    /// no effect.
     ///
     /// # Panics
     ///
     /// This method panics if called outside of a Tokio runtime.
-    pub fn spawn<F>(&mut self, task: F)
+    pub fn spawn<F>(&mut self, task: F) -> AbortHandle
     where
         F: Future<Output = T>,
         F: Send + 'static,
         T: Send,
     {
-        self.insert(crate::spawn(task));
+        self.insert((), crate::spawn(task))
     }
 
-    /// Spawn the provided task on the provided runtime and store it in this `TaskSet`.
-    pub fn spawn_on<F>(&mut self, task: F, handle: &Handle)
+    /// Spawn the provided task on the provided runtime and store it in this `TaskSet`, returning
+    /// an [`AbortHandle`] for the spawned task.
+    pub fn spawn_on<F>(&mut self, task: F, handle: &Handle) -> AbortHandle
     where
         F: Future<Output = T>,
         F: Send + 'static,
         T: Send,
     {
-        self.insert(handle.spawn(task));
+        self.insert((), handle.spawn(task))
     }
 
-    /// Spawn the provided task on the current [`LocalSet`] and store it in this `TaskSet`.
+    /// Spawn the provided task on the current [`LocalSet`] and store it in this `TaskSet`,
+    /// returning an [`AbortHandle`] for the spawned task.
     ///
     /// # Panics
     ///
     /// This method panics if it is called outside of a `LocalSet`.
     ///
     /// [`LocalSet`]: crate::task::LocalSet
-    pub fn spawn_local<F>(&mut self, task: F)
+    pub fn spawn_local<F>(&mut self, task: F) -> AbortHandle
     where
         F: Future<Output = T>,
         F: 'static,
     {
-        self.insert(crate::task::spawn_local(task));
+        self.insert((), crate::task::spawn_local(task))
     }
 
-    /// Spawn the provided task on the provided [`LocalSet`] and store it in this `TaskSet`.
+    /// Spawn the provided task on the provided [`LocalSet`] and store it in this `TaskSet`,
+    /// returning an [`AbortHandle`] for the spawned task.
     ///
     /// [`LocalSet`]: crate::task::LocalSet
-    pub fn spawn_local_on<F>(&mut self, task: F, local_set: &LocalSet)
+    pub fn spawn_local_on<F>(&mut self, task: F, local_set: &LocalSet) -> AbortHandle
     where
         F: Future<Output = T>,
         F: 'static,
     {
-        self.insert(local_set.spawn_local(task));
+        self.insert((), local_set.spawn_local(task))
     }
 
-    fn insert(&mut self, jh: JoinHandle<T>) {
-        let mut entry = self.inner.insert_idle(jh);
+    /// Spawn the provided task once the set has a free slot, returning an [`AbortHandle`] for it.
+    ///
+    /// If the `TaskSet` was created with [`with_capacity_limit`] and already holds that many
+    /// tasks, this waits for one of them to complete before spawning `task`. The completed
+    /// task's result is buffered and handed back by the next call to [`join_one`] (or
+    /// [`poll_join_one`]), so no result is lost while waiting for a slot.
+    ///
+    /// If the set has no limit, this behaves exactly like [`spawn`].
+    ///
+    /// [`with_capacity_limit`]: TaskSet::with_capacity_limit
+    /// [`join_one`]: TaskSet::join_one
+    /// [`poll_join_one`]: TaskSet::poll_join_one
+    /// [`spawn`]: TaskSet::spawn
+    pub async fn spawn_when_ready<F>(&mut self, task: F) -> AbortHandle
+    where
+        F: Future<Output = T>,
+        F: Send + 'static,
+        T: Send,
+    {
+        if let Some(limit) = self.limit {
+            if self.len() >= limit {
+                if let Some(res) = crate::future::poll_fn(|cx| self.poll_inner_one(cx))
+                    .await
+                    .transpose()
+                {
+                    self.buffered.push_back(res);
+                }
+            }
+        }
 
-        // Set the waker that is notified when the task completes.
-        entry.with_value_and_context(|jh, ctx| jh.set_join_waker(ctx.waker()));
+        self.spawn(task)
     }
 
     /// Wait until one of the tasks in the set completes and returns its output.
@@ -157,49 +390,80 @@ impl<T: 'static> TaskSet<T> {
     /// Note that this method may return `Poll::Pending` even if one of the tasks has completed.
     /// This can happen if the coop budget is reached.
     pub fn poll_join_one(&mut self, cx: &mut Context<'_>) -> Poll<Result<Option<T>, JoinError>> {
-        // The call to `pop_notified` moves the entry to the `idle` list. It is moved back to
-        // the `notified` list if the waker is notified in the `poll` call below.
-        let mut entry = match self.inner.pop_notified(cx.waker()) {
-            Some(entry) => entry,
-            None => {
-                if self.is_empty() {
-                    return Poll::Ready(Ok(None));
-                } else {
-                    // The waker was set by `pop_notified`.
-                    return Poll::Pending;
-                }
-            }
-        };
+        match self.poll_join_one_with_key(cx) {
+            Poll::Ready(Ok(opt)) => Poll::Ready(Ok(opt.map(|(_, value)| value))),
+            Poll::Ready(Err((_, err))) => Poll::Ready(Err(err)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
 
-        let res = entry.with_value_and_context(|jh, ctx| Pin::new(jh).poll(ctx));
+    /// Aborts all tasks and waits for them to finish shutting down.
+    ///
+    /// This is useful when tasks hold resources that must be released before proceeding: unlike
+    /// simply dropping the `TaskSet`, this method waits for every aborted (or already finished)
+    /// task to actually be removed from the set before returning, so their resources are
+    /// guaranteed to have been released.
+    ///
+    /// Once this method returns, the `TaskSet` is empty.
+    pub async fn shutdown(&mut self) {
+        self.abort_all();
 
-        if let Poll::Ready(res) = res {
-            entry.remove();
-            Poll::Ready(Some(res).transpose())
-        } else {
-            // A JoinHandle generally wont emit a wakeup without being ready unless
-            // the coop limit has been reached. We yield to the executor in this
-            // case.
-            cx.waker().wake_by_ref();
-            Poll::Pending
+        while !self.is_empty() || !self.buffered.is_empty() {
+            // Errors (panics or cancellations) are expected here and are not reported; we only
+            // care about draining the set.
+            let _ = self.join_one().await;
         }
     }
+
+    /// Waits for all tasks in the set to complete, without aborting them, and collects their
+    /// outputs in completion order.
+    ///
+    /// This consumes the `TaskSet`, so callers can fan work out with [`spawn`] and then gather
+    /// every result with a single call, rather than writing a manual [`join_one`] loop.
+    ///
+    /// [`spawn`]: TaskSet::spawn
+    /// [`join_one`]: TaskSet::join_one
+    pub async fn join_all(mut self) -> Vec<Result<T, JoinError>> {
+        let mut output = Vec::with_capacity(self.len());
+
+        loop {
+            match self.join_one().await {
+                Ok(Some(value)) => output.push(Ok(value)),
+                Err(err) => output.push(Err(err)),
+                Ok(None) => break,
+            }
+        }
+
+        output
+    }
 }
 
-impl<T> Drop for TaskSet<T> {
+impl<T, K> Drop for TaskSet<T, K> {
     fn drop(&mut self) {
-        self.inner.drain(|join_handle| join_handle.abort());
+        self.inner.drain(|(_, join_handle)| join_handle.abort());
     }
 }
 
-impl<T> fmt::Debug for TaskSet<T> {
+impl<T, K> fmt::Debug for TaskSet<T, K> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("TaskSet").field("len", &self.len()).finish()
     }
 }
 
-impl<T> Default for TaskSet<T> {
+impl<T, K> Default for TaskSet<T, K> {
     fn default() -> Self {
         Self::new()
     }
 }
+
+// No field of a `TaskSet` is ever pinned, so it is `Unpin` whatever `T` and `K` are. The
+// `buffered` queue would otherwise tie this to `T: Unpin`, which `poll_next` below relies on.
+impl<T, K> Unpin for TaskSet<T, K> {}
+
+impl<T: 'static> futures_core::Stream for TaskSet<T> {
+    type Item = Result<T, JoinError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.poll_join_one(cx).map(Result::transpose)
+    }
+}
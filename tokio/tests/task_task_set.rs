@@ -0,0 +1,119 @@
+#![warn(rust_2018_idioms)]
+#![cfg(feature = "full")]
+
+use std::collections::HashMap;
+use std::future::pending;
+
+use tokio::task::TaskSet;
+
+#[tokio::test]
+async fn spawn_when_ready_enforces_capacity_limit() {
+    let mut set = TaskSet::with_capacity_limit(2);
+
+    for i in 0..10 {
+        set.spawn_when_ready(async move { i }).await;
+        assert!(
+            set.len() <= 2,
+            "set grew past its capacity limit: len() == {}",
+            set.len()
+        );
+    }
+
+    let mut seen = [false; 10];
+    while let Some(i) = set.join_one().await.unwrap() {
+        seen[i] = true;
+    }
+    for (i, seen) in seen.into_iter().enumerate() {
+        assert!(seen, "task {i} was never observed as completed");
+    }
+}
+
+#[tokio::test]
+async fn abort_handle_cancels_single_task() {
+    let mut set = TaskSet::new();
+    let handle = set.spawn(pending::<()>());
+
+    handle.abort();
+
+    match set.join_one().await {
+        Ok(_) => panic!("expected the aborted task to surface a cancelled JoinError"),
+        Err(err) => assert!(err.is_cancelled()),
+    }
+}
+
+#[tokio::test]
+async fn abort_handle_cancels_single_keyed_task() {
+    let mut set = TaskSet::new();
+    let handle = set.spawn_with_key("victim", pending::<()>());
+
+    handle.abort();
+
+    match set.join_one_with_key().await {
+        Ok(_) => panic!("expected the aborted task to surface a cancelled JoinError"),
+        Err((key, err)) => {
+            assert_eq!(key, "victim");
+            assert!(err.is_cancelled());
+        }
+    }
+}
+
+#[tokio::test]
+async fn spawn_with_key_returns_key_alongside_output() {
+    let mut set = TaskSet::new();
+    set.spawn_with_key("a", async { 1 });
+    set.spawn_with_key("b", async { 2 });
+
+    let mut results = HashMap::new();
+    while let Some((key, value)) = set.join_one_with_key().await.unwrap() {
+        results.insert(key, value);
+    }
+
+    assert_eq!(results.get("a"), Some(&1));
+    assert_eq!(results.get("b"), Some(&2));
+}
+
+#[tokio::test]
+async fn join_one_with_key_returns_key_on_panic() {
+    let mut set = TaskSet::new();
+    set.spawn_with_key("boom", async { panic!("boom") });
+
+    match set.join_one_with_key().await {
+        Ok(_) => panic!("expected the spawned task to panic"),
+        Err((key, err)) => {
+            assert_eq!(key, "boom");
+            assert!(err.is_panic());
+        }
+    }
+}
+
+#[tokio::test]
+async fn shutdown_aborts_and_drains_everything() {
+    let mut set = TaskSet::new();
+
+    for _ in 0..3 {
+        set.spawn(pending::<()>());
+    }
+    assert_eq!(set.len(), 3);
+
+    set.shutdown().await;
+
+    assert!(set.is_empty());
+}
+
+#[tokio::test]
+async fn join_all_collects_every_output() {
+    let mut set = TaskSet::new();
+    for i in 0..5 {
+        set.spawn(async move { i });
+    }
+
+    let mut results: Vec<i32> = set
+        .join_all()
+        .await
+        .into_iter()
+        .map(Result::unwrap)
+        .collect();
+    results.sort_unstable();
+
+    assert_eq!(results, vec![0, 1, 2, 3, 4]);
+}